@@ -1,437 +1,1147 @@
 use super::{
-    BitArrayOption, BitArraySegment, CallArg, Clause, RecordBeingUpdated, Statement, UntypedExpr,
-    UntypedRecordUpdateArg, UntypedStatement,
+    AssignmentKind, BitArrayOption, BitArraySegment, CallArg, Clause, Pattern, RecordBeingUpdated,
+    SrcSpan, Statement, UntypedExpr, UntypedRecordUpdateArg, UntypedStatement,
 };
+use ecow::{eco_format, EcoString};
+use std::collections::HashMap;
 
-type Cont<'a> = fn(&UntypedExpr) -> UntypedExpr;
+/// A CPS continuation: a callback run on a value the pass has produced, so
+/// that callers can layer their own transformation over the ANF/CPS output
+/// without re-walking the tree themselves. Unlike a bare function pointer,
+/// this can close over whatever context the caller needs.
+pub type Cont<'a> = dyn FnMut(&UntypedExpr) -> UntypedExpr + 'a;
 
-pub fn to_cps(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
+/// Maps the synthetic span of a node the CPS pass fabricated to the real
+/// source span it was derived from, so later phases (diagnostics, the
+/// pretty-printer, ...) can attribute a generated node back to the user code
+/// it came from instead of reporting the synthetic span as if it were
+/// written by hand. Every entry maps straight to a real source span in one
+/// hop, even when the synthetic node was built on top of another synthetic
+/// node (see `CpsFolder::synthesize`) — callers never need to walk a chain.
+pub type ProvenanceMap = HashMap<SrcSpan, SrcSpan>;
+
+/// Structural recursion over `UntypedExpr`/`UntypedStatement`, split out from
+/// the variants themselves so that transforms don't have to re-match and
+/// rebuild the whole tree just to touch a handful of cases. Implementors
+/// override only the variants they care about; everything else falls
+/// through to `walk_expr`/`walk_statement`, which perform the identity
+/// recursion that `cps.rs` used to duplicate in every helper.
+pub trait ExprFolder {
+    fn fold_expr(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        walk_expr(self, expr)
+    }
+
+    fn fold_statement(&mut self, statement: &UntypedStatement) -> UntypedStatement {
+        walk_statement(self, statement)
+    }
+
+    fn fold_bit_array_option(
+        &mut self,
+        option: &BitArrayOption<UntypedExpr>,
+    ) -> BitArrayOption<UntypedExpr> {
+        walk_bit_array_option(self, option)
+    }
+}
+
+pub fn walk_expr<F: ExprFolder + ?Sized>(folder: &mut F, expr: &UntypedExpr) -> UntypedExpr {
     match expr {
         // Atomic expressions
         UntypedExpr::Int { .. }
         | UntypedExpr::Float { .. }
         | UntypedExpr::String { .. }
-        | UntypedExpr::Var { .. } => cps_atom(expr, k),
+        | UntypedExpr::Var { .. } => expr.clone(),
 
         // Block expressions
-        UntypedExpr::Block { .. } => cps_block(expr, k),
+        UntypedExpr::Block { statements, .. } => UntypedExpr::Block {
+            location: expr.location(),
+            statements: statements
+                .clone()
+                .mapped(|stmt| folder.fold_statement(&stmt)),
+        },
 
         // Collection expressions
-        UntypedExpr::List { .. } => cps_list(expr, k),
-        UntypedExpr::Tuple { .. } => cps_tuple(expr, k),
-        UntypedExpr::BitArray { .. } => cps_bit_array(expr, k),
+        UntypedExpr::List { elements, tail, .. } => UntypedExpr::List {
+            location: expr.location(),
+            elements: elements.iter().map(|elem| folder.fold_expr(elem)).collect(),
+            tail: tail.as_ref().map(|tail| Box::new(folder.fold_expr(tail))),
+        },
+        UntypedExpr::Tuple { elems, .. } => UntypedExpr::Tuple {
+            location: expr.location(),
+            elems: elems.iter().map(|elem| folder.fold_expr(elem)).collect(),
+        },
+        UntypedExpr::BitArray { segments, .. } => UntypedExpr::BitArray {
+            location: expr.location(),
+            segments: segments
+                .iter()
+                .map(|segment| BitArraySegment {
+                    value: Box::new(folder.fold_expr(&segment.value)),
+                    options: segment
+                        .options
+                        .iter()
+                        .map(|option| folder.fold_bit_array_option(option))
+                        .collect(),
+                    ..segment.clone()
+                })
+                .collect(),
+        },
 
         // Control flow expressions
-        UntypedExpr::Case { .. } => cps_case(expr, k),
-        UntypedExpr::Fn { .. } => cps_fn(expr, k),
+        UntypedExpr::Case {
+            subjects, clauses, ..
+        } => UntypedExpr::Case {
+            location: expr.location(),
+            subjects: subjects
+                .iter()
+                .map(|subject| folder.fold_expr(subject))
+                .collect(),
+            clauses: clauses.as_ref().map(|clauses| {
+                clauses
+                    .iter()
+                    .map(|clause| Clause {
+                        then: folder.fold_expr(&clause.then),
+                        ..clause.clone()
+                    })
+                    .collect()
+            }),
+        },
+        UntypedExpr::Fn {
+            kind,
+            end_of_head_byte_index,
+            arguments,
+            body,
+            return_annotation,
+            ..
+        } => UntypedExpr::Fn {
+            location: expr.location(),
+            kind: *kind,
+            end_of_head_byte_index: *end_of_head_byte_index,
+            arguments: arguments.clone(),
+            body: body.clone().mapped(|stmt| folder.fold_statement(&stmt)),
+            return_annotation: return_annotation.clone(),
+        },
 
         // Operation expressions
-        UntypedExpr::Call { .. } => cps_call(expr, k),
-        UntypedExpr::BinOp { .. } => cps_bin_op(expr, k),
-        UntypedExpr::PipeLine { .. } => cps_pipe_line(expr, k),
+        UntypedExpr::Call { fun, arguments, .. } => UntypedExpr::Call {
+            location: expr.location(),
+            fun: Box::new(folder.fold_expr(fun)),
+            arguments: arguments
+                .iter()
+                .map(|arg| CallArg {
+                    value: folder.fold_expr(&arg.value),
+                    ..arg.clone()
+                })
+                .collect(),
+        },
+        UntypedExpr::BinOp {
+            name, left, right, ..
+        } => UntypedExpr::BinOp {
+            location: expr.location(),
+            name: *name,
+            left: Box::new(folder.fold_expr(left)),
+            right: Box::new(folder.fold_expr(right)),
+        },
+        UntypedExpr::PipeLine { expressions } => UntypedExpr::PipeLine {
+            expressions: expressions.clone().mapped(|expr| folder.fold_expr(&expr)),
+        },
 
         // Access expressions
-        UntypedExpr::FieldAccess { .. } => cps_field_access(expr, k),
-        UntypedExpr::TupleIndex { .. } => cps_tuple_index(expr, k),
+        UntypedExpr::FieldAccess {
+            label_location,
+            label,
+            container,
+            ..
+        } => UntypedExpr::FieldAccess {
+            location: expr.location(),
+            label: label.clone(),
+            label_location: *label_location,
+            container: Box::new(folder.fold_expr(container)),
+        },
+        UntypedExpr::TupleIndex { index, tuple, .. } => UntypedExpr::TupleIndex {
+            location: expr.location(),
+            index: *index,
+            tuple: Box::new(folder.fold_expr(tuple)),
+        },
 
         // Update expressions
-        UntypedExpr::RecordUpdate { .. } => cps_record_update(expr, k),
+        UntypedExpr::RecordUpdate {
+            constructor,
+            record,
+            arguments,
+            ..
+        } => UntypedExpr::RecordUpdate {
+            location: expr.location(),
+            constructor: Box::new(folder.fold_expr(constructor)),
+            record: RecordBeingUpdated {
+                base: Box::new(folder.fold_expr(record.base.as_ref())),
+                location: record.location,
+            },
+            arguments: arguments
+                .iter()
+                .map(|arg| UntypedRecordUpdateArg {
+                    value: folder.fold_expr(&arg.value),
+                    ..arg.clone()
+                })
+                .collect(),
+        },
 
         // Unary operations
-        UntypedExpr::NegateBool { .. } => cps_negate_bool(expr, k),
-        UntypedExpr::NegateInt { .. } => cps_negate_int(expr, k),
+        UntypedExpr::NegateBool { value, .. } => UntypedExpr::NegateBool {
+            location: expr.location(),
+            value: Box::new(folder.fold_expr(value)),
+        },
+        UntypedExpr::NegateInt { value, .. } => UntypedExpr::NegateInt {
+            location: expr.location(),
+            value: Box::new(folder.fold_expr(value)),
+        },
 
         // Side effect expressions
-        UntypedExpr::Todo { .. } => cps_todo(expr, k),
-        UntypedExpr::Panic { .. } => cps_panic(expr, k),
-        UntypedExpr::Echo { .. } => cps_echo(expr, k),
+        UntypedExpr::Todo { kind, message, .. } => UntypedExpr::Todo {
+            kind: *kind,
+            location: expr.location(),
+            message: message
+                .as_ref()
+                .map(|message| Box::new(folder.fold_expr(message))),
+        },
+        UntypedExpr::Panic { message, .. } => UntypedExpr::Panic {
+            location: expr.location(),
+            message: message
+                .as_ref()
+                .map(|message| Box::new(folder.fold_expr(message))),
+        },
+        UntypedExpr::Echo { expression, .. } => UntypedExpr::Echo {
+            location: expr.location(),
+            expression: expression
+                .as_ref()
+                .map(|expression| Box::new(folder.fold_expr(expression))),
+        },
 
         // Other
-        UntypedExpr::Placeholder { location } => {
-            // Placeholder is handled directly
-            k(&UntypedExpr::Placeholder {
-                location: *location,
-            })
-        }
+        UntypedExpr::Placeholder { location } => UntypedExpr::Placeholder {
+            location: *location,
+        },
     }
 }
 
-fn cps_statement(statement: &UntypedStatement, k: &Cont<'_>) -> UntypedStatement {
+pub fn walk_statement<F: ExprFolder + ?Sized>(
+    folder: &mut F,
+    statement: &UntypedStatement,
+) -> UntypedStatement {
     match statement {
-        Statement::Expression(expr) => Statement::Expression(to_cps(expr, k)),
+        Statement::Expression(expr) => Statement::Expression(folder.fold_expr(expr)),
         Statement::Assignment(assignment) => Statement::Assignment(super::Assignment {
-            value: Box::new(to_cps(&assignment.value, k)),
+            value: Box::new(folder.fold_expr(&assignment.value)),
             ..assignment.clone()
         }),
         Statement::Use(use_stmt) => Statement::Use(super::Use {
-            call: Box::new(to_cps(&use_stmt.call, k)),
+            call: Box::new(folder.fold_expr(&use_stmt.call)),
             ..use_stmt.clone()
         }),
     }
 }
 
-fn cps_atom(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    k(expr)
+pub fn walk_bit_array_option<F: ExprFolder + ?Sized>(
+    folder: &mut F,
+    option: &BitArrayOption<UntypedExpr>,
+) -> BitArrayOption<UntypedExpr> {
+    match option {
+        BitArrayOption::Size {
+            value,
+            short_form,
+            location,
+        } => BitArrayOption::Size {
+            location: *location,
+            short_form: *short_form,
+            value: Box::new(folder.fold_expr(value.as_ref())),
+        },
+        _ => option.clone(),
+    }
 }
 
-fn cps_fn(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Fn {
-            kind,
-            end_of_head_byte_index,
-            arguments,
-            body,
-            return_annotation,
-            ..
-        } => {
-            let go_arg = super::UntypedArg {
-                location: expr.location(),
-                type_: (),
-                names: super::ArgNames::Named {
-                    name: "go".into(),
-                    location: expr.location(),
-                },
-                annotation: None,
-            };
-
-            let body_k: Cont<'_> = |stmt: &UntypedExpr| UntypedExpr::Call {
-                fun: Box::new(UntypedExpr::Var {
-                    location: stmt.location(),
-                    name: "go".into(),
-                }),
-                arguments: vec![CallArg {
-                    location: stmt.location(),
-                    value: stmt.clone(),
-                    label: None,
-                    implicit: None,
-                }],
-                location: stmt.location(),
-            };
-
-            let (init, last) = body.to_owned().split_off_last();
-            let body_cps = cps_statement(&last, &body_k);
-
-            let fn_expr = UntypedExpr::Fn {
-                location: expr.location(),
-                kind: *kind,
-                end_of_head_byte_index: *end_of_head_byte_index,
-                arguments: vec1::Vec1::from_vec_push(arguments.to_owned(), go_arg).to_vec(),
-                body: vec1::Vec1::from_vec_push(init, body_cps),
-                return_annotation: return_annotation.to_owned(),
-            };
-
-            k(&fn_expr)
-        }
-        _ => unreachable!(),
+/// Monotonic counter for the fresh variables this pass introduces, so that
+/// generated names (`_anf_0`, `_anf_1`, ...) never collide with anything the
+/// user wrote. It is a plain counter, not an interner: each `to_cps` call
+/// starts again from `_anf_0`.
+struct Gensym(u32);
+
+impl Gensym {
+    fn new() -> Self {
+        Gensym(0)
+    }
+
+    fn fresh(&mut self) -> EcoString {
+        let name = eco_format!("_anf_{}", self.0);
+        self.0 += 1;
+        name
     }
 }
 
-fn cps_list(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::List { elements, tail, .. } => {
-            let list = UntypedExpr::List {
-                location: expr.location(),
-                elements: elements.iter().map(|elem| to_cps(elem, k)).collect(),
-                tail: tail.as_ref().map(|tail| Box::new(to_cps(tail, k))),
-            };
-
-            k(&list)
+/// An expression is atomic if evaluating it has no observable effect and no
+/// sub-evaluation order to fix, i.e. it is already a value.
+fn is_atomic(expr: &UntypedExpr) -> bool {
+    matches!(
+        expr,
+        UntypedExpr::Var { .. }
+            | UntypedExpr::Int { .. }
+            | UntypedExpr::Float { .. }
+            | UntypedExpr::String { .. }
+    )
+}
+
+/// The CPS/ANF pass, implemented as an `ExprFolder` that overrides every
+/// variant with a non-atomic sub-expression (`Call`, `BinOp`, `Fn`, `Case`,
+/// `FieldAccess`, `TupleIndex`, `List`, `Tuple`, `BitArray`, `PipeLine`,
+/// `RecordUpdate`, `NegateBool`, `NegateInt`, `Todo`, `Panic`, `Echo`) so
+/// that each one normalizes its operands through `normalize_name` (`Fn`
+/// instead folds its body and wraps the tail value in `go(...)`, and `Case`
+/// folds each clause body in place); only the
+/// already-atomic variants (`Var`, `Int`, `Float`, `String`) and `Block`/
+/// `Placeholder` fall through to the shared `walk_expr`.
+struct CpsFolder<'a> {
+    gensym: Gensym,
+    /// Counts the synthetic spans minted so far; each one is given its own
+    /// unused corner of the byte-offset space (see `synthesize`) so it can
+    /// serve as a unique, `AstPtr`-style key into `provenance`.
+    next_synthetic_id: u32,
+    provenance: ProvenanceMap,
+    /// Run on the value wrapped in each synthesized `go(...)` tail call (see
+    /// `wrap_in_go`) and, once more, on the overall result in `to_cps`.
+    k: &'a mut Cont<'a>,
+}
+
+impl<'a> CpsFolder<'a> {
+    fn new(k: &'a mut Cont<'a>) -> Self {
+        Self {
+            gensym: Gensym::new(),
+            next_synthetic_id: 0,
+            provenance: HashMap::new(),
+            k,
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_call(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Call { fun, arguments, .. } => {
-            let call = UntypedExpr::Call {
-                location: expr.location(),
-                fun: Box::new(to_cps(fun, k)),
-                arguments: arguments
+    /// Mints a fresh span for a node that doesn't exist in the user's
+    /// source (an ANF temporary, the synthesized `go` argument, a hoisting
+    /// `Block`, ...) and records in `provenance` that it was derived from
+    /// `origin`, so later phases can still attribute diagnostics to real
+    /// source instead of the synthetic span.
+    ///
+    /// Synthetic spans are carved out of the top of the `u32` byte-offset
+    /// space, which no real file is large enough to reach, so they can
+    /// never be confused with a genuine source span. Every call site below
+    /// that fabricates a node routes its location through here rather than
+    /// reusing `origin` directly, for exactly this reason.
+    ///
+    /// `origin` is itself resolved through any existing `provenance` entry
+    /// first. A node built from an already-synthetic child (e.g. the
+    /// `Block` that `with_bindings` wraps around a call whose own argument
+    /// needed hoisting) would otherwise record its origin as that child's
+    /// synthetic span rather than real source, forcing every consumer of
+    /// the map to walk the chain itself. Resolving here keeps every entry
+    /// exactly one hop from real source.
+    fn synthesize(&mut self, origin: SrcSpan) -> SrcSpan {
+        let id = self.next_synthetic_id;
+        self.next_synthetic_id += 1;
+
+        let span = SrcSpan {
+            start: u32::MAX - (id * 2) - 1,
+            end: u32::MAX - (id * 2),
+        };
+
+        let origin = self.provenance.get(&origin).copied().unwrap_or(origin);
+        let _ = self.provenance.insert(span, origin);
+        span
+    }
+
+    /// Wraps any hoisted `bindings` around `expr`, sequencing them as a
+    /// `Block` so they run immediately before `expr` is evaluated. Returns
+    /// `expr` unchanged when nothing needed hoisting.
+    fn with_bindings(
+        &mut self,
+        mut bindings: Vec<UntypedStatement>,
+        expr: UntypedExpr,
+        origin: SrcSpan,
+    ) -> UntypedExpr {
+        if bindings.is_empty() {
+            return expr;
+        }
+
+        bindings.push(Statement::Expression(expr));
+        UntypedExpr::Block {
+            location: self.synthesize(origin),
+            statements: vec1::Vec1::try_from_vec(bindings)
+                .expect("with_bindings always pushes at least one statement"),
+        }
+    }
+
+    /// The `normalize-name` step of the ANF transform: fully folds `expr`,
+    /// then, if the result isn't already atomic, hoists it into a fresh `let
+    /// v = <folded expr>` pushed onto `bindings` and returns `Var(v)` in its
+    /// place.
+    fn normalize_name(
+        &mut self,
+        expr: &UntypedExpr,
+        bindings: &mut Vec<UntypedStatement>,
+    ) -> UntypedExpr {
+        let normalized = self.fold_expr(expr);
+
+        if is_atomic(&normalized) {
+            return normalized;
+        }
+
+        let location = self.synthesize(normalized.location());
+        let name = self.gensym.fresh();
+
+        bindings.push(Statement::Assignment(super::Assignment {
+            location,
+            value: Box::new(normalized),
+            pattern: Pattern::Variable {
+                location,
+                name: name.clone(),
+            },
+            annotation: None,
+            kind: AssignmentKind::Let,
+        }));
+
+        UntypedExpr::Var { location, name }
+    }
+
+    fn fold_call(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Call { fun, arguments, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let fun = Box::new(self.normalize_name(fun, &mut bindings));
+                let arguments = arguments
                     .iter()
                     .map(|arg| CallArg {
-                        value: to_cps(&arg.value, k),
+                        value: self.normalize_name(&arg.value, &mut bindings),
                         ..arg.clone()
                     })
-                    .collect(),
-            };
+                    .collect();
 
-            k(&call)
-        }
-        _ => unreachable!(),
-    }
-}
+                let call = UntypedExpr::Call {
+                    location,
+                    fun,
+                    arguments,
+                };
 
-fn cps_bin_op(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::BinOp {
-            name, left, right, ..
-        } => {
-            let bin_op = UntypedExpr::BinOp {
-                location: expr.location(),
-                name: *name,
-                left: Box::new(to_cps(left, k)),
-                right: Box::new(to_cps(right, k)),
-            };
-
-            k(&bin_op)
+                self.with_bindings(bindings, call, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_pipe_line(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::PipeLine { expressions, .. } => {
-            let pipe_line = UntypedExpr::PipeLine {
-                expressions: expressions.clone().mapped(|expr| to_cps(&expr, k)),
-            };
+    fn fold_bin_op(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::BinOp {
+                name, left, right, ..
+            } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let left = Box::new(self.normalize_name(left, &mut bindings));
+                let right = Box::new(self.normalize_name(right, &mut bindings));
+
+                let bin_op = UntypedExpr::BinOp {
+                    location,
+                    name: *name,
+                    left,
+                    right,
+                };
 
-            k(&pipe_line)
+                self.with_bindings(bindings, bin_op, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_case(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Case {
-            subjects, clauses, ..
-        } => {
-            let case = UntypedExpr::Case {
-                location: expr.location(),
-                subjects: subjects.iter().map(|subject| to_cps(subject, k)).collect(),
-                clauses: clauses.as_ref().map(|clauses| {
+    fn fold_case(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Case {
+                subjects, clauses, ..
+            } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let subjects = subjects
+                    .iter()
+                    .map(|subject| self.normalize_name(subject, &mut bindings))
+                    .collect();
+
+                // Each clause is folded independently, so any bindings
+                // hoisted while converting one arm stay local to that arm's
+                // own (nested) `Block` rather than leaking into another.
+                let clauses = clauses.as_ref().map(|clauses| {
                     clauses
                         .iter()
                         .map(|clause| Clause {
-                            then: to_cps(&clause.then, k),
+                            then: self.fold_expr(&clause.then),
                             ..clause.clone()
                         })
                         .collect()
-                }),
-            };
+                });
 
-            k(&case)
-        }
-        _ => unreachable!(),
-    }
-}
+                let case = UntypedExpr::Case {
+                    location,
+                    subjects,
+                    clauses,
+                };
 
-fn cps_field_access(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::FieldAccess {
-            label_location,
-            label,
-            container,
-            ..
-        } => {
-            let field_access = UntypedExpr::FieldAccess {
-                location: expr.location(),
-                label: label.clone(),
-                label_location: *label_location,
-                container: Box::new(to_cps(container, k)),
-            };
-
-            k(&field_access)
+                self.with_bindings(bindings, case, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_tuple(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Tuple { elems, .. } => {
-            let tuple = UntypedExpr::Tuple {
-                location: expr.location(),
-                elems: elems.iter().map(|elem| to_cps(elem, k)).collect(),
-            };
+    fn fold_field_access(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::FieldAccess {
+                label_location,
+                label,
+                container,
+                ..
+            } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let container = Box::new(self.normalize_name(container, &mut bindings));
 
-            k(&tuple)
-        }
-        _ => unreachable!(),
-    }
-}
+                let field_access = UntypedExpr::FieldAccess {
+                    location,
+                    label: label.clone(),
+                    label_location: *label_location,
+                    container,
+                };
 
-fn cps_tuple_index(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::TupleIndex { index, tuple, .. } => {
-            let tuple_index = UntypedExpr::TupleIndex {
-                location: expr.location(),
-                index: *index,
-                tuple: Box::new(to_cps(tuple, k)),
-            };
-
-            k(&tuple_index)
+                self.with_bindings(bindings, field_access, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_block(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Block { statements, .. } => {
-            let block = UntypedExpr::Block {
-                location: expr.location(),
-                statements: statements.clone().mapped(|stmt| cps_statement(&stmt, k)),
-            };
+    fn fold_tuple_index(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::TupleIndex { index, tuple, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let tuple = Box::new(self.normalize_name(tuple, &mut bindings));
 
-            k(&block)
-        }
-        _ => unreachable!(),
-    }
-}
+                let tuple_index = UntypedExpr::TupleIndex {
+                    location,
+                    index: *index,
+                    tuple,
+                };
 
-fn cps_todo(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Todo { kind, message, .. } => {
-            let todo = UntypedExpr::Todo {
-                kind: *kind,
-                location: expr.location(),
-                message: message.as_ref().map(|message| Box::new(to_cps(message, k))),
-            };
-
-            k(&todo)
+                self.with_bindings(bindings, tuple_index, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_panic(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Panic { message, .. } => {
-            let panic = UntypedExpr::Panic {
-                location: expr.location(),
-                message: message.as_ref().map(|message| Box::new(to_cps(message, k))),
-            };
+    fn fold_list(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::List { elements, tail, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let elements = elements
+                    .iter()
+                    .map(|elem| self.normalize_name(elem, &mut bindings))
+                    .collect();
+                let tail = tail
+                    .as_ref()
+                    .map(|tail| Box::new(self.normalize_name(tail, &mut bindings)));
 
-            k(&panic)
+                let list = UntypedExpr::List {
+                    location,
+                    elements,
+                    tail,
+                };
+
+                self.with_bindings(bindings, list, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_echo(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::Echo { expression, .. } => {
-            let echo = UntypedExpr::Echo {
-                location: expr.location(),
-                expression: expression
-                    .as_ref()
-                    .map(|expression| Box::new(to_cps(expression, k))),
-            };
+    fn fold_tuple(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Tuple { elems, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let elems = elems
+                    .iter()
+                    .map(|elem| self.normalize_name(elem, &mut bindings))
+                    .collect();
+
+                let tuple = UntypedExpr::Tuple { location, elems };
 
-            k(&echo)
+                self.with_bindings(bindings, tuple, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_bit_array_option(
-    option: &BitArrayOption<UntypedExpr>,
-    k: &Cont<'_>,
-) -> BitArrayOption<UntypedExpr> {
-    match option {
-        BitArrayOption::Size {
-            value,
-            short_form,
-            location,
-        } => BitArrayOption::Size {
-            location: *location,
-            short_form: *short_form,
-            value: Box::new(to_cps(value.as_ref(), k)),
-        },
-        _ => option.clone(),
+    fn normalize_bit_array_option(
+        &mut self,
+        option: &BitArrayOption<UntypedExpr>,
+        bindings: &mut Vec<UntypedStatement>,
+    ) -> BitArrayOption<UntypedExpr> {
+        match option {
+            BitArrayOption::Size {
+                value,
+                short_form,
+                location,
+            } => BitArrayOption::Size {
+                location: *location,
+                short_form: *short_form,
+                value: Box::new(self.normalize_name(value.as_ref(), bindings)),
+            },
+            _ => option.clone(),
+        }
     }
-}
 
-fn cps_bit_array(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::BitArray { segments, .. } => {
-            let bit_array = UntypedExpr::BitArray {
-                location: expr.location(),
-                segments: segments
+    fn fold_bit_array(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::BitArray { segments, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let segments = segments
                     .iter()
                     .map(|segment| BitArraySegment {
-                        value: Box::new(to_cps(&segment.value, k)),
+                        value: Box::new(self.normalize_name(&segment.value, &mut bindings)),
                         options: segment
                             .options
                             .iter()
-                            .map(|option| cps_bit_array_option(option, k))
+                            .map(|option| self.normalize_bit_array_option(option, &mut bindings))
                             .collect(),
                         ..segment.clone()
                     })
-                    .collect(),
-            };
+                    .collect();
+
+                let bit_array = UntypedExpr::BitArray { location, segments };
 
-            k(&bit_array)
+                self.with_bindings(bindings, bit_array, location)
+            }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_record_being_updated(record: &RecordBeingUpdated, k: &Cont<'_>) -> RecordBeingUpdated {
-    RecordBeingUpdated {
-        base: Box::new(to_cps(record.base.as_ref(), k)),
-        location: record.location,
+    fn fold_pipe_line(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::PipeLine { expressions } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let expressions = expressions
+                    .clone()
+                    .mapped(|expr| self.normalize_name(&expr, &mut bindings));
+
+                let pipe_line = UntypedExpr::PipeLine { expressions };
+
+                self.with_bindings(bindings, pipe_line, location)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fold_record_update(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::RecordUpdate {
+                constructor,
+                record,
+                arguments,
+                ..
+            } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let constructor = Box::new(self.normalize_name(constructor, &mut bindings));
+                let record = RecordBeingUpdated {
+                    base: Box::new(self.normalize_name(record.base.as_ref(), &mut bindings)),
+                    location: record.location,
+                };
+                let arguments = arguments
+                    .iter()
+                    .map(|arg| UntypedRecordUpdateArg {
+                        value: self.normalize_name(&arg.value, &mut bindings),
+                        ..arg.clone()
+                    })
+                    .collect();
+
+                let record_update = UntypedExpr::RecordUpdate {
+                    location,
+                    constructor,
+                    record,
+                    arguments,
+                };
+
+                self.with_bindings(bindings, record_update, location)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fold_negate_bool(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::NegateBool { value, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let value = Box::new(self.normalize_name(value, &mut bindings));
+
+                let negate_bool = UntypedExpr::NegateBool { location, value };
+
+                self.with_bindings(bindings, negate_bool, location)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fold_negate_int(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::NegateInt { value, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let value = Box::new(self.normalize_name(value, &mut bindings));
+
+                let negate_int = UntypedExpr::NegateInt { location, value };
+
+                self.with_bindings(bindings, negate_int, location)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fold_todo(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Todo { kind, message, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let message = message
+                    .as_ref()
+                    .map(|message| Box::new(self.normalize_name(message, &mut bindings)));
+
+                let todo = UntypedExpr::Todo {
+                    kind: *kind,
+                    location,
+                    message,
+                };
+
+                self.with_bindings(bindings, todo, location)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fold_panic(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Panic { message, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let message = message
+                    .as_ref()
+                    .map(|message| Box::new(self.normalize_name(message, &mut bindings)));
+
+                let panic = UntypedExpr::Panic { location, message };
+
+                self.with_bindings(bindings, panic, location)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fold_echo(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Echo { expression, .. } => {
+                let location = expr.location();
+                let mut bindings = Vec::new();
+                let expression = expression
+                    .as_ref()
+                    .map(|expression| Box::new(self.normalize_name(expression, &mut bindings)));
+
+                let echo = UntypedExpr::Echo {
+                    location,
+                    expression,
+                };
+
+                self.with_bindings(bindings, echo, location)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn fold_fn(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Fn {
+                kind,
+                end_of_head_byte_index,
+                arguments,
+                body,
+                return_annotation,
+                ..
+            } => {
+                let go_name: EcoString = "go".into();
+                // This is the extra continuation parameter appended to every
+                // lambda; it has no counterpart in the user's source.
+                let go_location = self.synthesize(expr.location());
+
+                let go_arg = super::UntypedArg {
+                    location: go_location,
+                    type_: (),
+                    names: super::ArgNames::Named {
+                        name: go_name.clone(),
+                        location: go_location,
+                    },
+                    annotation: None,
+                };
+
+                let (init, last) = body.to_owned().split_off_last();
+                let init = init
+                    .into_iter()
+                    .map(|stmt| self.fold_statement(&stmt))
+                    .collect();
+                let last = match last {
+                    Statement::Expression(e) => {
+                        let value = self.fold_expr(&e);
+                        Statement::Expression(self.wrap_in_go(&go_name, value))
+                    }
+                    Statement::Assignment(assignment) => {
+                        let value = self.fold_expr(&assignment.value);
+                        Statement::Assignment(super::Assignment {
+                            value: Box::new(self.wrap_in_go(&go_name, value)),
+                            ..assignment
+                        })
+                    }
+                    Statement::Use(use_stmt) => {
+                        let value = self.fold_expr(&use_stmt.call);
+                        Statement::Use(super::Use {
+                            call: Box::new(self.wrap_in_go(&go_name, value)),
+                            ..use_stmt
+                        })
+                    }
+                };
+
+                UntypedExpr::Fn {
+                    location: expr.location(),
+                    kind: *kind,
+                    end_of_head_byte_index: *end_of_head_byte_index,
+                    arguments: vec1::Vec1::from_vec_push(arguments.to_owned(), go_arg).to_vec(),
+                    body: vec1::Vec1::from_vec_push(init, last),
+                    return_annotation: return_annotation.to_owned(),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Wraps `value` in a call to the synthesized `go` continuation
+    /// parameter, i.e. rewrites a tail position `value` into `go(value)`.
+    /// `value` is run through `k` first, so the caller's continuation sees
+    /// every tail position inside every nested closure, not just the
+    /// outermost result.
+    fn wrap_in_go(&mut self, go_name: &EcoString, value: UntypedExpr) -> UntypedExpr {
+        let value = (self.k)(&value);
+        let location = self.synthesize(value.location());
+        UntypedExpr::Call {
+            fun: Box::new(UntypedExpr::Var {
+                location,
+                name: go_name.clone(),
+            }),
+            arguments: vec![CallArg {
+                location,
+                value,
+                label: None,
+                implicit: None,
+            }],
+            location,
+        }
     }
 }
 
-fn cps_record_update_arg(arg: &UntypedRecordUpdateArg, k: &Cont<'_>) -> UntypedRecordUpdateArg {
-    UntypedRecordUpdateArg {
-        value: to_cps(&arg.value, k),
-        ..arg.clone()
+impl<'a> ExprFolder for CpsFolder<'a> {
+    fn fold_expr(&mut self, expr: &UntypedExpr) -> UntypedExpr {
+        match expr {
+            UntypedExpr::Call { .. } => self.fold_call(expr),
+            UntypedExpr::BinOp { .. } => self.fold_bin_op(expr),
+            UntypedExpr::Fn { .. } => self.fold_fn(expr),
+            UntypedExpr::Case { .. } => self.fold_case(expr),
+            UntypedExpr::FieldAccess { .. } => self.fold_field_access(expr),
+            UntypedExpr::TupleIndex { .. } => self.fold_tuple_index(expr),
+            UntypedExpr::List { .. } => self.fold_list(expr),
+            UntypedExpr::Tuple { .. } => self.fold_tuple(expr),
+            UntypedExpr::BitArray { .. } => self.fold_bit_array(expr),
+            UntypedExpr::PipeLine { .. } => self.fold_pipe_line(expr),
+            UntypedExpr::RecordUpdate { .. } => self.fold_record_update(expr),
+            UntypedExpr::NegateBool { .. } => self.fold_negate_bool(expr),
+            UntypedExpr::NegateInt { .. } => self.fold_negate_int(expr),
+            UntypedExpr::Todo { .. } => self.fold_todo(expr),
+            UntypedExpr::Panic { .. } => self.fold_panic(expr),
+            UntypedExpr::Echo { .. } => self.fold_echo(expr),
+            _ => walk_expr(self, expr),
+        }
     }
 }
 
-fn cps_record_update(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::RecordUpdate {
-            constructor,
-            record,
-            arguments,
-            ..
-        } => {
-            let record_update = UntypedExpr::RecordUpdate {
-                location: expr.location(),
-                constructor: Box::new(to_cps(constructor, k)),
-                record: cps_record_being_updated(record, k),
-                arguments: arguments
-                    .iter()
-                    .map(|arg| cps_record_update_arg(arg, k))
-                    .collect(),
-            };
+/// Rewrites `expr` into CPS/ANF form and returns it alongside the
+/// [`ProvenanceMap`] recording where each synthesized node came from, so
+/// that later phases can attribute diagnostics and pretty-printing to real
+/// user locations instead of the fabricated spans.
+///
+/// `k` is run on every synthesized closure's tail value (see `wrap_in_go`)
+/// and, once more, on the overall transformed expression, so a caller can
+/// layer a further rewrite over the ANF/CPS output without re-walking the
+/// result itself. Pass `&mut |e| e.clone()` if there's nothing to do.
+pub fn to_cps<'a>(expr: &UntypedExpr, k: &'a mut Cont<'a>) -> (UntypedExpr, ProvenanceMap) {
+    let mut folder = CpsFolder::new(k);
+    let transformed = folder.fold_expr(expr);
+    let transformed = (folder.k)(&transformed);
+    (transformed, folder.provenance)
+}
 
-            k(&record_update)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span() -> SrcSpan {
+        SrcSpan { start: 0, end: 0 }
+    }
+
+    fn var(name: &str) -> UntypedExpr {
+        UntypedExpr::Var {
+            location: span(),
+            name: name.into(),
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_negate_bool(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::NegateBool { value, .. } => {
-            let negate_bool = UntypedExpr::NegateBool {
-                location: expr.location(),
-                value: Box::new(to_cps(value, k)),
-            };
+    fn call(fun: UntypedExpr, args: Vec<UntypedExpr>) -> UntypedExpr {
+        UntypedExpr::Call {
+            location: span(),
+            fun: Box::new(fun),
+            arguments: args
+                .into_iter()
+                .map(|value| CallArg {
+                    location: span(),
+                    value,
+                    label: None,
+                    implicit: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn is_let_binding(statement: &UntypedStatement) -> bool {
+        matches!(statement, Statement::Assignment(_))
+    }
+
+    /// `to_cps` with a no-op continuation, for tests that only care about
+    /// the ANF shape and don't exercise `k` itself.
+    fn to_cps_identity(expr: &UntypedExpr) -> (UntypedExpr, ProvenanceMap) {
+        to_cps(expr, &mut |e: &UntypedExpr| e.clone())
+    }
+
+    #[test]
+    fn nested_calls_are_flattened_into_sequenced_bindings() {
+        // f(g(x), h(y))
+        let expr = call(
+            var("f"),
+            vec![
+                call(var("g"), vec![var("x")]),
+                call(var("h"), vec![var("y")]),
+            ],
+        );
+
+        let (transformed, _) = to_cps_identity(&expr);
+
+        let UntypedExpr::Block { statements, .. } = transformed else {
+            panic!("expected nested calls to be hoisted into a block, got {transformed:?}");
+        };
+
+        // One hoisted binding per nested call, then the flattened call.
+        assert_eq!(statements.len(), 3);
+        assert!(is_let_binding(&statements[0]));
+        assert!(is_let_binding(&statements[1]));
 
-            k(&negate_bool)
+        let Statement::Expression(UntypedExpr::Call { fun, arguments, .. }) = &statements[2] else {
+            panic!("expected the final statement to be the flattened call");
+        };
+        assert!(is_atomic(fun));
+        for arg in arguments {
+            assert!(is_atomic(&arg.value));
         }
-        _ => unreachable!(),
     }
-}
 
-fn cps_negate_int(expr: &UntypedExpr, k: &Cont<'_>) -> UntypedExpr {
-    match expr {
-        UntypedExpr::NegateInt { value, .. } => {
-            let negate_int = UntypedExpr::NegateInt {
-                location: expr.location(),
-                value: Box::new(to_cps(value, k)),
-            };
+    #[test]
+    fn case_subject_requiring_hoisting_is_bound_to_a_fresh_variable() {
+        // case f(x) { y -> y }
+        let subject = call(var("f"), vec![var("x")]);
+        let clause = Clause {
+            location: span(),
+            pattern: vec![Pattern::Variable {
+                location: span(),
+                name: "y".into(),
+            }],
+            alternative_patterns: vec![],
+            guard: None,
+            then: var("y"),
+        };
+        let expr = UntypedExpr::Case {
+            location: span(),
+            subjects: vec![subject].into_iter().collect(),
+            clauses: Some(vec![clause].into_iter().collect()),
+        };
+
+        let (transformed, _) = to_cps_identity(&expr);
+
+        let UntypedExpr::Block { statements, .. } = &transformed else {
+            panic!("expected the subject to be hoisted into a block, got {transformed:?}");
+        };
+        assert_eq!(statements.len(), 2);
+        assert!(is_let_binding(&statements[0]));
+
+        let Statement::Expression(UntypedExpr::Case { subjects, .. }) = &statements[1] else {
+            panic!("expected the final statement to be the Case expression");
+        };
+        assert!(is_atomic(&subjects[0]));
+    }
+
+    #[test]
+    fn field_access_container_requiring_hoisting_is_bound_to_a_fresh_variable() {
+        // f(id).name
+        let expr = UntypedExpr::FieldAccess {
+            location: span(),
+            label: "name".into(),
+            label_location: span(),
+            container: Box::new(call(var("f"), vec![var("id")])),
+        };
+
+        let (transformed, _) = to_cps_identity(&expr);
+
+        let UntypedExpr::Block { statements, .. } = transformed else {
+            panic!("expected the call to be hoisted into a block, got {transformed:?}");
+        };
+        assert_eq!(statements.len(), 2);
+        assert!(is_let_binding(&statements[0]));
+
+        let Statement::Expression(UntypedExpr::FieldAccess { container, .. }) = &statements[1]
+        else {
+            panic!("expected the final statement to be the field access");
+        };
+        assert!(is_atomic(container));
+    }
+
+    #[test]
+    fn tuple_index_container_requiring_hoisting_is_bound_to_a_fresh_variable() {
+        // f(id).0
+        let expr = UntypedExpr::TupleIndex {
+            location: span(),
+            index: 0,
+            tuple: Box::new(call(var("f"), vec![var("id")])),
+        };
+
+        let (transformed, _) = to_cps_identity(&expr);
+
+        let UntypedExpr::Block { statements, .. } = transformed else {
+            panic!("expected the call to be hoisted into a block, got {transformed:?}");
+        };
+        assert_eq!(statements.len(), 2);
+
+        let Statement::Expression(UntypedExpr::TupleIndex { tuple, .. }) = &statements[1] else {
+            panic!("expected the final statement to be the tuple index");
+        };
+        assert!(is_atomic(tuple));
+    }
+
+    #[test]
+    fn list_elements_requiring_hoisting_are_bound_to_fresh_variables() {
+        // [f(x), g(y)]
+        let expr = UntypedExpr::List {
+            location: span(),
+            elements: vec![
+                call(var("f"), vec![var("x")]),
+                call(var("g"), vec![var("y")]),
+            ],
+            tail: None,
+        };
+
+        let (transformed, _) = to_cps_identity(&expr);
 
-            k(&negate_int)
+        let UntypedExpr::Block { statements, .. } = transformed else {
+            panic!("expected the list elements to be hoisted into a block, got {transformed:?}");
+        };
+
+        // One hoisted binding per element, then the flattened list.
+        assert_eq!(statements.len(), 3);
+        assert!(is_let_binding(&statements[0]));
+        assert!(is_let_binding(&statements[1]));
+
+        let Statement::Expression(UntypedExpr::List { elements, .. }) = &statements[2] else {
+            panic!("expected the final statement to be the flattened list");
+        };
+        for elem in elements {
+            assert!(is_atomic(elem));
         }
-        _ => unreachable!(),
+    }
+
+    #[test]
+    fn provenance_map_records_origin_of_synthesized_nodes() {
+        // f(g(x))
+        let expr = call(var("f"), vec![call(var("g"), vec![var("x")])]);
+
+        let (transformed, provenance) = to_cps_identity(&expr);
+
+        let UntypedExpr::Block { statements, .. } = &transformed else {
+            panic!("expected the call to be hoisted into a block, got {transformed:?}");
+        };
+
+        // The hoisted `let v = g(x)` binding's location is synthetic and
+        // should be traced back to `g(x)`'s own (unsynthesized) location.
+        let Statement::Assignment(assignment) = &statements[0] else {
+            panic!("expected the first statement to be the hoisted binding");
+        };
+        assert_eq!(provenance.get(&assignment.location), Some(&span()));
+
+        // The wrapping block is synthetic too, and traces back to the
+        // location of the outer call it replaced.
+        let UntypedExpr::Block { location, .. } = &transformed else {
+            unreachable!()
+        };
+        assert_eq!(provenance.get(location), Some(&span()));
+    }
+
+    #[test]
+    fn provenance_resolves_through_a_nested_synthetic_block_in_one_hop() {
+        // f(g(h(x)))
+        let expr = call(
+            var("f"),
+            vec![call(var("g"), vec![call(var("h"), vec![var("x")])])],
+        );
+
+        let (transformed, provenance) = to_cps_identity(&expr);
+
+        let UntypedExpr::Block { statements, .. } = &transformed else {
+            panic!("expected the outer call to be hoisted into a block, got {transformed:?}");
+        };
+
+        // `g(h(x))` is itself hoisted into a synthetic Block (since its own
+        // argument `h(x)` needed hoisting) before being bound here, so
+        // naively recording this binding's origin as that inner Block's
+        // location would point at another synthetic span. It should
+        // resolve straight through to the real, original span instead.
+        let Statement::Assignment(outer_binding) = &statements[0] else {
+            panic!("expected the first statement to be the hoisted binding for g(h(x))");
+        };
+        assert!(matches!(*outer_binding.value, UntypedExpr::Block { .. }));
+        assert_eq!(provenance.get(&outer_binding.location), Some(&span()));
+    }
+
+    #[test]
+    fn continuation_runs_on_the_final_transformed_result() {
+        // f(x)
+        let expr = call(var("f"), vec![var("x")]);
+
+        let mut k = |e: &UntypedExpr| call(var("mark"), vec![e.clone()]);
+        let (transformed, _) = to_cps(&expr, &mut k);
+
+        let UntypedExpr::Call { fun, arguments, .. } = &transformed else {
+            panic!("expected the continuation's wrapping call, got {transformed:?}");
+        };
+        let UntypedExpr::Var { name, .. } = fun.as_ref() else {
+            panic!("expected the wrapping call's function to be a Var");
+        };
+        assert_eq!(name, "mark");
+        assert_eq!(arguments.len(), 1);
     }
 }